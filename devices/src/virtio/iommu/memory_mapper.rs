@@ -0,0 +1,93 @@
+// Copyright 2022 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Defines `MemoryMapper`, the trait the virtio-iommu device uses to drive a backend's DMA
+//! mapping table (and, where the backend supports it, dirty-page tracking over that table)
+//! without needing to know whether the backend is VFIO, an in-process IOMMU, or something else.
+
+use thiserror::Error;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemoryError;
+
+use crate::vfio::VfioError;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to get host address: {0}")]
+    GetHostAddress(GuestMemoryError),
+    #[error("integer overflow")]
+    IntegerOverflow,
+    #[error("iova region overlaps with an existing region")]
+    IovaRegionOverlap,
+    #[error("no mapping covers the requested range")]
+    NoSuchMappingInRange,
+    #[error("operation not implemented")]
+    Unimplemented,
+    #[error("vfio error: {0}")]
+    Vfio(VfioError),
+}
+
+/// Access permissions of a mapping, matching the virtio-iommu `VIRTIO_IOMMU_MAP_F_*` flags.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Permission {
+    Read = 1,
+    Write = 2,
+    RW = 3,
+}
+
+/// One entry of a mapper's DMA mapping table: `size` bytes starting at guest-physical `gpa` are
+/// reachable by the device at IOVA `iova`, with the given `perm`ission.
+#[derive(Copy, Clone)]
+pub struct MappingInfo {
+    pub iova: u64,
+    pub gpa: GuestAddress,
+    pub size: u64,
+    pub perm: Permission,
+}
+
+/// A contiguous run of guest memory backing part of an IOVA range, returned by
+/// [`Translate::translate`].
+pub struct MemRegion {
+    pub gpa: GuestAddress,
+    pub len: u64,
+    pub perm: Permission,
+}
+
+/// Exposes a device's DMA mapping table to the virtio-iommu device.
+pub trait MemoryMapper: Send {
+    fn add_map(&mut self, map: MappingInfo) -> Result<(), Error>;
+    fn remove_map(&mut self, iova_start: u64, size: u64) -> Result<(), Error>;
+    fn get_mask(&self) -> Result<u64, Error>;
+    fn supports_detach(&self) -> bool;
+    fn id(&self) -> u32;
+
+    /// Whether this mapper's backend can track which mapped pages the device has written to.
+    /// Defaults to unsupported; backends that can wrap the relevant hardware facility (e.g.
+    /// VFIO's `VFIO_IOMMU_DIRTY_PAGES`) override this along with the three methods below.
+    fn supports_dirty_log(&self) -> bool {
+        false
+    }
+
+    /// Starts dirty-page logging. Only valid to call if `supports_dirty_log()` is true.
+    fn start_dirty_log(&mut self) -> Result<(), Error> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Stops dirty-page logging.
+    fn stop_dirty_log(&mut self) -> Result<(), Error> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Returns the guest addresses of pages written to since logging started (or since the last
+    /// call to this method), within `[iova, iova + size)`.
+    fn get_dirty_bitmap(&self, iova: u64, size: u64) -> Result<Vec<GuestAddress>, Error> {
+        let _ = (iova, size);
+        Err(Error::Unimplemented)
+    }
+}
+
+/// Resolves IOVA ranges mapped through a [`MemoryMapper`] back to the guest memory they cover.
+pub trait Translate {
+    fn translate(&self, iova: u64, size: u64) -> Result<Vec<MemRegion>, Error>;
+}
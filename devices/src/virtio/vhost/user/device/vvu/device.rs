@@ -25,9 +25,8 @@ use base::Event;
 use base::RawDescriptor;
 use cros_async::EventAsync;
 use cros_async::Executor;
-use futures::pin_mut;
-use futures::select;
-use futures::FutureExt;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 use vmm_vhost::connection::vfio::Device as VfioDeviceTrait;
 use vmm_vhost::connection::vfio::Endpoint as VfioEndpoint;
@@ -37,6 +36,7 @@ use vmm_vhost::message::*;
 
 use crate::virtio::vhost::user::device::vvu::pci::QueueNotifier;
 use crate::virtio::vhost::user::device::vvu::pci::VvuPciDevice;
+use crate::virtio::vhost::user::device::vvu::queue::QueueSnapshot;
 use crate::virtio::vhost::user::device::vvu::queue::UserQueue;
 use crate::virtio::vhost::vhost_header_from_bytes;
 use crate::virtio::vhost::HEADER_LEN;
@@ -59,6 +59,17 @@ impl VfioSender {
     }
 }
 
+impl Clone for VfioSender {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            // The duplicated descriptor still refers to the same underlying eventfd, so
+            // writes from any queue pair's clone wake up the same reader.
+            evt: self.evt.try_clone().expect("VfioSender evt clone"),
+        }
+    }
+}
+
 struct VfioReceiver {
     receiver: Receiver<Vec<u8>>,
     buf: Vec<u8>,
@@ -142,6 +153,20 @@ impl VfioReceiver {
 
         Ok(size)
     }
+
+    // The bytes (and consumption offset) of a message that's only partially delivered to the
+    // frontend/backend, if any. Captured across `sleep()`/`snapshot()` so a restored device
+    // doesn't lose a message that was mid-delivery; this is the practical equivalent of
+    // capturing the raw rxq_evt counter, which isn't meaningful on its own once queues are
+    // torn down and re-created.
+    fn pending(&self) -> (Vec<u8>, usize) {
+        (self.buf.clone(), self.offset)
+    }
+
+    fn set_pending(&mut self, buf: Vec<u8>, offset: usize) {
+        self.buf = buf;
+        self.offset = offset;
+    }
 }
 
 // Data queued to send on an endpoint.
@@ -150,6 +175,27 @@ struct EndpointTxBuffer {
     bytes: Vec<u8>,
 }
 
+// The control command the driver sends to announce how many of the configured rx/tx queue
+// pairs it actually intends to use, mirroring virtio-net's `VIRTIO_NET_CTRL_MQ` handshake.
+#[derive(Copy, Clone)]
+struct SetQueuePairsCommand {
+    queue_pairs: u16,
+}
+
+impl SetQueuePairsCommand {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let raw: [u8; 2] = buf
+            .try_into()
+            .map_err(|_| anyhow!("control command must be 2 bytes, got {}", buf.len()))?;
+        Ok(Self {
+            queue_pairs: u16::from_le_bytes(raw),
+        })
+    }
+}
+
+const CTRL_ACK: u8 = 0;
+const CTRL_ERR: u8 = 1;
+
 // Utility class for writing an input vhost-user byte stream to the vvu
 // tx virtqueue as discrete vhost-user messages.
 struct Queue {
@@ -192,12 +238,27 @@ impl Queue {
     }
 }
 
+// Mirrors `Queue`, but for the rxq side of a forwarding pair. Wrapped in `Arc<Mutex<_>>` (like
+// `Queue` already is) so that `sleep()` can park it outside the worker thread without losing
+// any state, and `wake()` can hand the same instance to a freshly spawned worker.
+struct RxQueue {
+    rxq: UserQueue,
+    rxq_notifier: QueueNotifier,
+}
+
+// Mirrors `RxQueue`, but for the control queue.
+struct CtrlQueue {
+    ctrlq: UserQueue,
+    ctrlq_notifier: QueueNotifier,
+}
+
 async fn process_rxq(
+    pair_index: usize,
     evt: EventAsync,
-    mut rxq: UserQueue,
-    rxq_notifier: QueueNotifier,
+    rxq: Arc<Mutex<RxQueue>>,
     frontend_sender: VfioSender,
     backend_sender: VfioSender,
+    active_queue_pairs: Arc<Mutex<usize>>,
 ) -> Result<()> {
     loop {
         if let Err(e) = evt.next_val().await {
@@ -205,7 +266,13 @@ async fn process_rxq(
             continue;
         }
 
-        while let Some(slice) = rxq.read_data()? {
+        if pair_index >= *active_queue_pairs.lock() {
+            // The driver hasn't activated this pair (yet); drop the notification.
+            continue;
+        }
+
+        let mut rxq = rxq.lock();
+        while let Some(slice) = rxq.rxq.read_data()? {
             if slice.size() < HEADER_LEN {
                 bail!("rxq message too short: {}", slice.size());
             }
@@ -234,60 +301,165 @@ async fn process_rxq(
             .send(buf)
             .context("send failed")?;
         }
-        rxq_notifier.notify();
+        rxq.rxq_notifier.notify();
     }
 }
 
-async fn process_txq(evt: EventAsync, txq: Arc<Mutex<Queue>>) -> Result<()> {
+async fn process_txq(
+    pair_index: usize,
+    evt: EventAsync,
+    txq: Arc<Mutex<Queue>>,
+    active_queue_pairs: Arc<Mutex<usize>>,
+) -> Result<()> {
     loop {
         if let Err(e) = evt.next_val().await {
             error!("Failed to read the next queue event: {}", e);
             continue;
         }
 
+        if pair_index >= *active_queue_pairs.lock() {
+            continue;
+        }
+
         txq.lock().txq.ack_used()?;
     }
 }
 
+// Handles `SetQueuePairsCommand`s sent by the driver on the control queue, validating the
+// requested count against `max_queue_pairs` and updating `active_queue_pairs` so that
+// `process_rxq`/`process_txq` know which of the configured pairs to service.
+async fn process_ctrlq(
+    evt: EventAsync,
+    ctrlq: Arc<Mutex<CtrlQueue>>,
+    max_queue_pairs: usize,
+    active_queue_pairs: Arc<Mutex<usize>>,
+) -> Result<()> {
+    loop {
+        if let Err(e) = evt.next_val().await {
+            error!("Failed to read the next queue event: {}", e);
+            continue;
+        }
+
+        let mut ctrlq = ctrlq.lock();
+        while let Some(slice) = ctrlq.ctrlq.read_data()? {
+            let mut buf = vec![0_u8; slice.size()];
+            slice.copy_to(&mut buf);
+
+            let status = match SetQueuePairsCommand::parse(&buf) {
+                Ok(cmd) if cmd.queue_pairs as usize > max_queue_pairs || cmd.queue_pairs == 0 => {
+                    error!(
+                        "requested {} queue pairs is outside the supported range of 1 to {}",
+                        cmd.queue_pairs, max_queue_pairs
+                    );
+                    CTRL_ERR
+                }
+                Ok(cmd) => {
+                    *active_queue_pairs.lock() = cmd.queue_pairs as usize;
+                    CTRL_ACK
+                }
+                Err(e) => {
+                    error!("failed to parse control queue command: {}", e);
+                    CTRL_ERR
+                }
+            };
+            ctrlq
+                .ctrlq
+                .write(&[status])
+                .context("failed to ack ctrlq command")?;
+        }
+        ctrlq.ctrlq_notifier.notify();
+    }
+}
+
+// Resolves as soon as `sleep()` signals `stop_evt`. Real queue tasks only ever return via an
+// `Err` (they loop forever otherwise), so a task set that resolves with `Ok(())` can only be
+// this one winning -- `run_worker` relies on that to tell a clean stop apart from a failure.
+async fn wait_for_stop(evt: EventAsync) -> Result<()> {
+    evt.next_val().await.context("failed to read stop event")?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_worker(
     ex: Executor,
-    rx_queue: UserQueue,
-    rx_irq: Event,
-    rx_notifier: QueueNotifier,
+    rx_queues: &[Arc<Mutex<RxQueue>>],
+    rx_irqs: &[Event],
     frontend_sender: VfioSender,
     backend_sender: VfioSender,
-    tx_queue: Arc<Mutex<Queue>>,
-    tx_irq: Event,
+    tx_queues: &[Arc<Mutex<Queue>>],
+    tx_irqs: &[Event],
+    ctrlq: Arc<Mutex<CtrlQueue>>,
+    ctrlq_irq: &Event,
+    max_queue_pairs: usize,
+    active_queue_pairs: Arc<Mutex<usize>>,
+    stop_evt: Event,
 ) -> Result<()> {
-    let rx_irq = EventAsync::new(rx_irq, &ex).context("failed to create async event")?;
-    let rxq = process_rxq(
-        rx_irq,
-        rx_queue,
-        rx_notifier,
-        frontend_sender,
-        backend_sender,
-    );
-    pin_mut!(rxq);
-
-    let tx_irq = EventAsync::new(tx_irq, &ex).context("failed to create async event")?;
-    let txq = process_txq(tx_irq, Arc::clone(&tx_queue));
-    pin_mut!(txq);
-
-    let done = async {
-        select! {
-            res = rxq.fuse() => res.context("failed to handle rxq"),
-            res = txq.fuse() => res.context("failed to handle txq"),
-        }
-    };
+    let mut tasks: Vec<std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>>>>> =
+        Vec::new();
+
+    for (pair_index, (rxq, rx_irq)) in rx_queues.iter().zip(rx_irqs).enumerate() {
+        let rx_irq = rx_irq.try_clone().context("failed to clone rx_irq")?;
+        let rx_irq = EventAsync::new(rx_irq, &ex).context("failed to create async event")?;
+        tasks.push(Box::pin(process_rxq(
+            pair_index,
+            rx_irq,
+            Arc::clone(rxq),
+            frontend_sender.clone(),
+            backend_sender.clone(),
+            active_queue_pairs.clone(),
+        )));
+    }
+
+    for (pair_index, (txq, tx_irq)) in tx_queues.iter().zip(tx_irqs).enumerate() {
+        let tx_irq = tx_irq.try_clone().context("failed to clone tx_irq")?;
+        let tx_irq = EventAsync::new(tx_irq, &ex).context("failed to create async event")?;
+        tasks.push(Box::pin(process_txq(
+            pair_index,
+            tx_irq,
+            Arc::clone(txq),
+            active_queue_pairs.clone(),
+        )));
+    }
+
+    let ctrlq_irq = ctrlq_irq.try_clone().context("failed to clone ctrlq_irq")?;
+    let ctrlq_irq = EventAsync::new(ctrlq_irq, &ex).context("failed to create async event")?;
+    tasks.push(Box::pin(process_ctrlq(
+        ctrlq_irq,
+        ctrlq,
+        max_queue_pairs,
+        active_queue_pairs,
+    )));
+
+    let stop_evt = EventAsync::new(stop_evt, &ex).context("failed to create async event")?;
+    tasks.push(Box::pin(wait_for_stop(stop_evt)));
+
+    let done = futures::future::select_all(tasks);
 
     match ex.run_until(done) {
-        Ok(_) => Ok(()),
+        Ok((res, _, _)) => res.context("failed to process a virtio-vhost-user queue"),
         Err(e) => {
             bail!("failed to process virtio-vhost-user queues: {}", e);
         }
     }
 }
 
+// Queue-pool state that's common to `DeviceState::Running` and `DeviceState::Asleep`: what's
+// left once the worker thread has been (or hasn't yet been) spawned around it.
+struct QueuePool {
+    // The `UserQueue`s below hold `VolatileSlice`s into this device's BAR mapping, so it must
+    // outlive them; keeping it here (rather than letting the worker thread's closure be its
+    // only owner) means `sleep()`/`wake()` cycling that thread doesn't drop it.
+    device: VvuPciDevice,
+    rx_queues: Vec<Arc<Mutex<RxQueue>>>,
+    rx_irqs: Vec<Event>,
+    tx_queues: Vec<Arc<Mutex<Queue>>>,
+    tx_irqs: Vec<Event>,
+    ctrlq: Arc<Mutex<CtrlQueue>>,
+    ctrlq_irq: Event,
+    max_queue_pairs: usize,
+    active_queue_pairs: Arc<Mutex<usize>>,
+}
+
 enum DeviceState {
     Initialized {
         // TODO(keiichiw): Update `VfioDeviceTrait::start()` to take `VvuPciDevice` so that we can
@@ -297,9 +469,39 @@ enum DeviceState {
     Running {
         rxq_receiver: VfioReceiver,
         tx_state: EndpointTxBuffer,
-
-        txq: Arc<Mutex<Queue>>,
+        pool: QueuePool,
+        stop_evt: Event,
+        worker_thread: thread::JoinHandle<()>,
+    },
+    // Parked by `sleep()`: the worker thread has exited and the queues are quiescent, but
+    // `pool` (and the byte-stream state alongside it) is kept around so `wake()` can respawn a
+    // worker, or `snapshot()` can capture it, without losing anything the driver already sent.
+    Asleep {
+        rxq_receiver: VfioReceiver,
+        tx_state: EndpointTxBuffer,
+        pool: QueuePool,
     },
+    // A placeholder held while data moves out of one variant and into another. It stays in
+    // place if `start()`, `sleep()` or `wake()` fails partway through, after which every further
+    // request to the device is rejected.
+    Poisoned,
+}
+
+/// The resumable state of a [`VvuDevice`], produced by [`VvuDevice::snapshot`] and consumed by
+/// [`VvuDevice::restore`]. `snapshot` puts the device to sleep itself (see [`VvuDevice::sleep`]);
+/// `restore` requires the device to be asleep already.
+#[derive(Serialize, Deserialize)]
+pub struct VvuDeviceSnapshot {
+    active_queue_pairs: usize,
+    rx_queues: Vec<QueueSnapshot>,
+    tx_queues: Vec<QueueSnapshot>,
+    ctrlq: QueueSnapshot,
+    tx_state_bytes: Vec<u8>,
+    rxq_pending_bytes: Vec<u8>,
+    rxq_pending_offset: usize,
+    backend_tx_state_bytes: Vec<u8>,
+    backend_rxq_pending_bytes: Vec<u8>,
+    backend_rxq_pending_offset: usize,
 }
 
 pub struct VvuDevice {
@@ -307,6 +509,21 @@ pub struct VvuDevice {
     frontend_rxq_evt: Event,
 
     backend_channel: Option<VfioEndpoint<SlaveReq, BackendChannel>>,
+
+    // The sender halves of the rxq/backend-rxq channels, and the eventfd the backend sender
+    // signals. `start()` creates these alongside the receivers that `DeviceState`/
+    // `BackendChannel` hold onto, and `wake()` reuses them to respawn a worker that feeds the
+    // very same receivers -- recreating the channels instead would strand any bytes already
+    // queued on the old one.
+    rxq_sender: Option<Sender<Vec<u8>>>,
+    backend_rxq_sender: Option<Sender<Vec<u8>>>,
+    backend_rxq_evt: Option<Event>,
+
+    // Shared with the `BackendChannel` handed out by `create_slave_request_endpoint()`, so that
+    // `snapshot()`/`restore()` can still reach its byte-stream state after ownership of the
+    // `BackendChannel` itself has been taken by the vhost-user backend session.
+    backend_rxq_receiver: Option<Arc<Mutex<VfioReceiver>>>,
+    backend_tx_state: Option<Arc<Mutex<EndpointTxBuffer>>>,
 }
 
 impl VvuDevice {
@@ -315,6 +532,269 @@ impl VvuDevice {
             state: DeviceState::Initialized { device },
             frontend_rxq_evt: Event::new().expect("failed to create VvuDevice's rxq_evt"),
             backend_channel: None,
+            rxq_sender: None,
+            backend_rxq_sender: None,
+            backend_rxq_evt: None,
+            backend_rxq_receiver: None,
+            backend_tx_state: None,
+        }
+    }
+
+    fn spawn_worker(&self, pool: &QueuePool) -> Result<(Event, thread::JoinHandle<()>)> {
+        let ex = Executor::new().context("Failed to create an executor")?;
+
+        let rxq_sender = self
+            .rxq_sender
+            .clone()
+            .context("VvuDevice has no rxq_sender")?;
+        let rxq_evt = self.frontend_rxq_evt.try_clone().context("rxq_evt clone")?;
+        let frontend_sender = VfioSender::new(rxq_sender, rxq_evt);
+
+        let backend_rxq_sender = self
+            .backend_rxq_sender
+            .clone()
+            .context("VvuDevice has no backend_rxq_sender")?;
+        let backend_rxq_evt = self
+            .backend_rxq_evt
+            .as_ref()
+            .context("VvuDevice has no backend_rxq_evt")?
+            .try_clone()
+            .context("backend_rxq_evt clone")?;
+        let backend_sender = VfioSender::new(backend_rxq_sender, backend_rxq_evt);
+
+        let stop_evt = Event::new().context("failed to create stop_evt")?;
+        let worker_stop_evt = stop_evt.try_clone().context("stop_evt clone")?;
+
+        let rx_queues = pool.rx_queues.clone();
+        let rx_irqs = pool
+            .rx_irqs
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .context("failed to clone rx_irqs")?;
+        let tx_queues = pool.tx_queues.clone();
+        let tx_irqs = pool
+            .tx_irqs
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .context("failed to clone tx_irqs")?;
+        let ctrlq = Arc::clone(&pool.ctrlq);
+        let ctrlq_irq = pool.ctrlq_irq.try_clone().context("ctrlq_irq clone")?;
+        let max_queue_pairs = pool.max_queue_pairs;
+        let active_queue_pairs = pool.active_queue_pairs.clone();
+
+        let join_handle = thread::Builder::new()
+            .name("virtio-vhost-user driver".to_string())
+            .spawn(move || {
+                if let Err(e) = run_worker(
+                    ex,
+                    &rx_queues,
+                    &rx_irqs,
+                    frontend_sender,
+                    backend_sender,
+                    &tx_queues,
+                    &tx_irqs,
+                    ctrlq,
+                    &ctrlq_irq,
+                    max_queue_pairs,
+                    active_queue_pairs,
+                    worker_stop_evt,
+                ) {
+                    error!("worker thread exited with error: {}", e);
+                }
+            })
+            .context("failed to spawn vvu worker thread")?;
+
+        Ok((stop_evt, join_handle))
+    }
+
+    /// Stops the rx/tx/ctrl worker thread, leaving all queue and byte-stream state intact so the
+    /// device can either be woken back up with [`VvuDevice::wake`] or captured with
+    /// [`VvuDevice::snapshot`].
+    pub fn sleep(&mut self) -> Result<()> {
+        if matches!(self.state, DeviceState::Asleep { .. }) {
+            return Ok(());
+        }
+        let old_state = std::mem::replace(&mut self.state, DeviceState::Poisoned);
+        let (rxq_receiver, tx_state, pool, stop_evt, worker_thread) = match old_state {
+            DeviceState::Initialized { device } => {
+                self.state = DeviceState::Initialized { device };
+                bail!("VvuDevice hasn't started yet");
+            }
+            DeviceState::Running {
+                rxq_receiver,
+                tx_state,
+                pool,
+                stop_evt,
+                worker_thread,
+            } => (rxq_receiver, tx_state, pool, stop_evt, worker_thread),
+            DeviceState::Asleep { .. } => unreachable!("checked above"),
+            DeviceState::Poisoned => bail!("VvuDevice is in an unusable state"),
+        };
+
+        stop_evt.write(1).context("failed to signal stop_evt")?;
+        worker_thread
+            .join()
+            .map_err(|_| anyhow!("vvu worker thread panicked"))?;
+
+        self.state = DeviceState::Asleep {
+            rxq_receiver,
+            tx_state,
+            pool,
+        };
+        Ok(())
+    }
+
+    /// Re-spawns the worker thread that [`VvuDevice::sleep`] tore down, resuming forwarding with
+    /// the same queue and byte-stream state it left off at.
+    pub fn wake(&mut self) -> Result<()> {
+        let old_state = std::mem::replace(&mut self.state, DeviceState::Poisoned);
+        let (rxq_receiver, tx_state, pool) = match old_state {
+            DeviceState::Asleep {
+                rxq_receiver,
+                tx_state,
+                pool,
+            } => (rxq_receiver, tx_state, pool),
+            other => {
+                self.state = other;
+                bail!("VvuDevice is not asleep");
+            }
+        };
+
+        let (stop_evt, worker_thread) = self.spawn_worker(&pool)?;
+
+        self.state = DeviceState::Running {
+            rxq_receiver,
+            tx_state,
+            pool,
+            stop_evt,
+            worker_thread,
+        };
+        Ok(())
+    }
+
+    /// Puts the device to sleep (if it isn't already) and captures its resumable state.
+    pub fn snapshot(&mut self) -> Result<VvuDeviceSnapshot> {
+        self.sleep().context("failed to sleep for snapshot")?;
+        let pool = match &self.state {
+            DeviceState::Asleep { pool, .. } => pool,
+            _ => unreachable!("sleep() always leaves the device Asleep"),
+        };
+        let (rxq_receiver, tx_state) = match &self.state {
+            DeviceState::Asleep {
+                rxq_receiver,
+                tx_state,
+                ..
+            } => (rxq_receiver, tx_state),
+            _ => unreachable!(),
+        };
+
+        let (rxq_pending_bytes, rxq_pending_offset) = rxq_receiver.pending();
+
+        // The backend channel's byte-stream state lives outside of `self.state` (see
+        // `backend_rxq_receiver`/`backend_tx_state`), since its owning `BackendChannel` may
+        // already have been handed off via `create_slave_request_endpoint()`. It's still shared
+        // with this `VvuDevice`, though, so it's always reachable here.
+        let (backend_rxq_pending_bytes, backend_rxq_pending_offset) = self
+            .backend_rxq_receiver
+            .as_ref()
+            .map(|r| r.lock().pending())
+            .unwrap_or_default();
+        let backend_tx_state_bytes = self
+            .backend_tx_state
+            .as_ref()
+            .map(|t| t.lock().bytes.clone())
+            .unwrap_or_default();
+
+        Ok(VvuDeviceSnapshot {
+            active_queue_pairs: *pool.active_queue_pairs.lock(),
+            rx_queues: pool
+                .rx_queues
+                .iter()
+                .map(|q| q.lock().rxq.snapshot())
+                .collect(),
+            tx_queues: pool
+                .tx_queues
+                .iter()
+                .map(|q| q.lock().txq.snapshot())
+                .collect(),
+            ctrlq: pool.ctrlq.lock().ctrlq.snapshot(),
+            tx_state_bytes: tx_state.bytes.clone(),
+            rxq_pending_bytes,
+            rxq_pending_offset,
+            backend_tx_state_bytes,
+            backend_rxq_pending_bytes,
+            backend_rxq_pending_offset,
+        })
+    }
+
+    /// Applies a snapshot captured by [`VvuDevice::snapshot`] and wakes the device back up. The
+    /// device must currently be asleep.
+    pub fn restore(&mut self, snapshot: VvuDeviceSnapshot) -> Result<()> {
+        let pool = match &mut self.state {
+            DeviceState::Asleep { pool, .. } => pool,
+            _ => bail!("VvuDevice must be asleep to restore a snapshot"),
+        };
+
+        if snapshot.rx_queues.len() != pool.rx_queues.len()
+            || snapshot.tx_queues.len() != pool.tx_queues.len()
+        {
+            bail!(
+                "snapshot queue count mismatch: got {} rx / {} tx, expected {} / {}",
+                snapshot.rx_queues.len(),
+                snapshot.tx_queues.len(),
+                pool.rx_queues.len(),
+                pool.tx_queues.len()
+            );
+        }
+
+        for (q, snap) in pool.rx_queues.iter().zip(snapshot.rx_queues) {
+            q.lock().rxq.restore(snap);
+        }
+        for (q, snap) in pool.tx_queues.iter().zip(snapshot.tx_queues) {
+            q.lock().txq.restore(snap);
+        }
+        pool.ctrlq.lock().ctrlq.restore(snapshot.ctrlq);
+        *pool.active_queue_pairs.lock() = snapshot.active_queue_pairs;
+
+        match &mut self.state {
+            DeviceState::Asleep {
+                rxq_receiver,
+                tx_state,
+                ..
+            } => {
+                tx_state.bytes = snapshot.tx_state_bytes;
+                rxq_receiver.set_pending(snapshot.rxq_pending_bytes, snapshot.rxq_pending_offset);
+            }
+            _ => unreachable!("checked above"),
+        }
+
+        if let Some(r) = &self.backend_rxq_receiver {
+            r.lock().set_pending(
+                snapshot.backend_rxq_pending_bytes,
+                snapshot.backend_rxq_pending_offset,
+            );
+        }
+        if let Some(t) = &self.backend_tx_state {
+            t.lock().bytes = snapshot.backend_tx_state_bytes;
+        }
+
+        self.wake()
+    }
+}
+
+impl Drop for VvuDevice {
+    fn drop(&mut self) {
+        // `DeviceState::Running` drops its fields in declaration order, which would free `pool`
+        // (and with it, `pool.device`'s BAR mapping that the worker thread's queues borrow from)
+        // before `worker_thread` -- and dropping a still-running `JoinHandle` detaches it rather
+        // than joining it, so the worker would go on dereferencing freed memory. Stop and join
+        // the worker first so callers don't have to remember to call `sleep()` before teardown.
+        if matches!(self.state, DeviceState::Running { .. }) {
+            if let Err(e) = self.sleep() {
+                error!("failed to stop vvu worker thread on drop: {}", e);
+            }
         }
     }
 }
@@ -327,77 +807,118 @@ impl VfioDeviceTrait for VvuDevice {
     fn start(&mut self) -> Result<()> {
         let device = match &mut self.state {
             DeviceState::Initialized { device } => device,
-            DeviceState::Running { .. } => {
+            DeviceState::Running { .. } | DeviceState::Asleep { .. } | DeviceState::Poisoned => {
                 bail!("VvuDevice has already started");
             }
         };
-        let ex = Executor::new().expect("Failed to create an executor");
 
         let mut irqs = mem::take(&mut device.irqs);
         let mut queues = mem::take(&mut device.queues);
         let mut queue_notifiers = mem::take(&mut device.queue_notifiers);
 
-        let rxq = queues.remove(0);
-        let rxq_irq = irqs.remove(0);
-        let rxq_notifier = queue_notifiers.remove(0);
+        // The device is configured with `max_queue_pairs` rx/tx pairs followed by a single
+        // control queue used to negotiate how many of those pairs the driver actually uses.
+        if queues.len() < 3 || queues.len() % 2 == 0 {
+            bail!(
+                "expected an odd number (>= 3) of vvu queues, got {}",
+                queues.len()
+            );
+        }
+        let max_queue_pairs = (queues.len() - 1) / 2;
+        let active_queue_pairs = Arc::new(Mutex::new(max_queue_pairs));
+
         // TODO: Can we use async channel instead so we don't need `rxq_evt`?
         let (rxq_sender, rxq_receiver) = channel();
-        let rxq_evt = self.frontend_rxq_evt.try_clone().expect("rxq_evt clone");
 
-        let txq = Arc::new(Mutex::new(Queue {
-            txq: queues.remove(0),
-            txq_notifier: queue_notifiers.remove(0),
+        let mut rx_queues = Vec::with_capacity(max_queue_pairs);
+        let mut rx_irqs = Vec::with_capacity(max_queue_pairs);
+        let mut tx_queues = Vec::with_capacity(max_queue_pairs);
+        let mut tx_irqs = Vec::with_capacity(max_queue_pairs);
+        for _ in 0..max_queue_pairs {
+            let rxq = queues.remove(0);
+            rx_irqs.push(irqs.remove(0));
+            let rxq_notifier = queue_notifiers.remove(0);
+            rx_queues.push(Arc::new(Mutex::new(RxQueue {
+                rxq,
+                rxq_notifier,
+            })));
+
+            let txq = Arc::new(Mutex::new(Queue {
+                txq: queues.remove(0),
+                txq_notifier: queue_notifiers.remove(0),
+            }));
+            tx_irqs.push(irqs.remove(0));
+            tx_queues.push(txq);
+        }
+        let ctrlq = queues.remove(0);
+        let ctrlq_irq = irqs.remove(0);
+        let ctrlq_notifier = queue_notifiers.remove(0);
+        let ctrlq = Arc::new(Mutex::new(CtrlQueue {
+            ctrlq,
+            ctrlq_notifier,
         }));
-        let txq_cloned = Arc::clone(&txq);
-        let txq_irq = irqs.remove(0);
+
+        // Queue pair 0 is the one the transport's own vhost-user control session rides on.
+        let txq = Arc::clone(&tx_queues[0]);
 
         let (backend_rxq_sender, backend_rxq_receiver) = channel();
         let backend_rxq_evt = Event::new().expect("failed to create VvuDevice's rxq_evt");
         let backend_rxq_evt2 = backend_rxq_evt.try_clone().expect("rxq_evt clone");
+        let backend_channel_evt = backend_rxq_evt.try_clone().expect("rxq_evt clone");
+        let backend_rxq_receiver = Arc::new(Mutex::new(VfioReceiver::new(
+            backend_rxq_receiver,
+            backend_rxq_evt,
+        )));
+        let backend_tx_state = Arc::new(Mutex::new(EndpointTxBuffer::default()));
         self.backend_channel = Some(VfioEndpoint::from(BackendChannel {
-            receiver: VfioReceiver::new(backend_rxq_receiver, backend_rxq_evt),
-            queue: txq.clone(),
-            tx_state: EndpointTxBuffer::default(),
+            evt: backend_channel_evt,
+            receiver: Arc::clone(&backend_rxq_receiver),
+            queue: txq,
+            tx_state: Arc::clone(&backend_tx_state),
         }));
 
-        let old_state = std::mem::replace(
-            &mut self.state,
-            DeviceState::Running {
-                rxq_receiver: VfioReceiver::new(
-                    rxq_receiver,
-                    self.frontend_rxq_evt
-                        .try_clone()
-                        .expect("frontend_rxq_evt clone"),
-                ),
-                tx_state: EndpointTxBuffer::default(),
-                txq,
-            },
-        );
-
-        let device = match old_state {
+        self.rxq_sender = Some(rxq_sender);
+        self.backend_rxq_sender = Some(backend_rxq_sender);
+        self.backend_rxq_evt = Some(backend_rxq_evt2);
+        self.backend_rxq_receiver = Some(backend_rxq_receiver);
+        self.backend_tx_state = Some(backend_tx_state);
+
+        let old_state = std::mem::replace(&mut self.state, DeviceState::Poisoned);
+        let mut device = match old_state {
             DeviceState::Initialized { device } => device,
             _ => unreachable!(),
         };
+        // Started synchronously (rather than inside the worker thread, as earlier revisions did)
+        // so the PCI-side handle can be kept on `QueuePool` instead of being dropped the moment
+        // the worker thread that used to own it exits for a sleep()/wake() cycle.
+        device.start().expect("failed to start device");
+
+        let pool = QueuePool {
+            device,
+            rx_queues,
+            rx_irqs,
+            tx_queues,
+            tx_irqs,
+            ctrlq,
+            ctrlq_irq,
+            max_queue_pairs,
+            active_queue_pairs,
+        };
 
-        let frontend_sender = VfioSender::new(rxq_sender, rxq_evt);
-        let backend_sender = VfioSender::new(backend_rxq_sender, backend_rxq_evt2);
-        thread::Builder::new()
-            .name("virtio-vhost-user driver".to_string())
-            .spawn(move || {
-                device.start().expect("failed to start device");
-                if let Err(e) = run_worker(
-                    ex,
-                    rxq,
-                    rxq_irq,
-                    rxq_notifier,
-                    frontend_sender,
-                    backend_sender,
-                    txq_cloned,
-                    txq_irq,
-                ) {
-                    error!("worker thread exited with error: {}", e);
-                }
-            })?;
+        let (stop_evt, worker_thread) = self.spawn_worker(&pool)?;
+
+        self.state = DeviceState::Running {
+            rxq_receiver: VfioReceiver::new(
+                rxq_receiver,
+                self.frontend_rxq_evt
+                    .try_clone()
+                    .expect("frontend_rxq_evt clone"),
+            ),
+            tx_state: EndpointTxBuffer::default(),
+            pool,
+            stop_evt,
+            worker_thread,
+        };
 
         Ok(())
     }
@@ -407,8 +928,11 @@ impl VfioDeviceTrait for VvuDevice {
             DeviceState::Initialized { .. } => {
                 bail!("VvuDevice hasn't started yet");
             }
-            DeviceState::Running { txq, tx_state, .. } => {
-                let mut queue = txq.lock();
+            DeviceState::Asleep { .. } | DeviceState::Poisoned => {
+                bail!("VvuDevice is asleep");
+            }
+            DeviceState::Running { pool, tx_state, .. } => {
+                let mut queue = pool.tx_queues[0].lock();
                 queue.send_bufs(iovs, fds, tx_state)
             }
         }
@@ -419,6 +943,9 @@ impl VfioDeviceTrait for VvuDevice {
             DeviceState::Initialized { .. } => Err(RecvIntoBufsError::Fatal(anyhow!(
                 "VvuDevice hasn't started yet"
             ))),
+            DeviceState::Asleep { .. } | DeviceState::Poisoned => {
+                Err(RecvIntoBufsError::Fatal(anyhow!("VvuDevice is asleep")))
+            }
             DeviceState::Running { rxq_receiver, .. } => rxq_receiver.recv_into_bufs(bufs),
         }
     }
@@ -432,16 +959,20 @@ impl VfioDeviceTrait for VvuDevice {
     }
 }
 
-// Struct which implements the Endpoint for backend messages.
+// Struct which implements the Endpoint for backend messages. `receiver` and `tx_state` are
+// shared (via `VvuDevice::backend_rxq_receiver`/`backend_tx_state`) rather than owned outright,
+// so that `VvuDevice::snapshot`/`restore` can still reach their byte-stream state once this
+// struct's ownership has been handed off to the vhost-user backend session.
 struct BackendChannel {
-    receiver: VfioReceiver,
+    evt: Event,
+    receiver: Arc<Mutex<VfioReceiver>>,
     queue: Arc<Mutex<Queue>>,
-    tx_state: EndpointTxBuffer,
+    tx_state: Arc<Mutex<EndpointTxBuffer>>,
 }
 
 impl VfioDeviceTrait for BackendChannel {
     fn event(&self) -> &Event {
-        &self.receiver.evt
+        &self.evt
     }
 
     fn start(&mut self) -> Result<()> {
@@ -449,11 +980,13 @@ impl VfioDeviceTrait for BackendChannel {
     }
 
     fn send_bufs(&mut self, iovs: &[IoSlice], fds: Option<&[RawFd]>) -> Result<usize> {
-        self.queue.lock().send_bufs(iovs, fds, &mut self.tx_state)
+        self.queue
+            .lock()
+            .send_bufs(iovs, fds, &mut self.tx_state.lock())
     }
 
     fn recv_into_bufs(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize, RecvIntoBufsError> {
-        self.receiver.recv_into_bufs(bufs)
+        self.receiver.lock().recv_into_bufs(bufs)
     }
 
     fn create_slave_request_endpoint(&mut self) -> Result<Box<dyn Endpoint<SlaveReq>>> {
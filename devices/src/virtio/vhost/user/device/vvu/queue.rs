@@ -0,0 +1,390 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `UserQueue` is a virtqueue implementation for the virtio-vhost-user (VVU) driver side of the
+//! transport: it reads descriptor chains written by the device-under-test's driver and writes
+//! the bytes of device replies back into them. Unlike the guest-facing virtqueue, its descriptor
+//! table, available/used rings (or descriptor ring, for the packed layout) live in the shared
+//! BAR memory that both ends of the VVU link can see, rather than in guest memory.
+
+use std::mem;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use data_model::VolatileMemory;
+use data_model::VolatileMemoryError;
+use data_model::VolatileSlice;
+use serde::Deserialize;
+use serde::Serialize;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+
+// Packed ring specific descriptor flags (virtio-v1.1 section 2.7.1).
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+#[derive(Copy, Clone, Default, AsBytes, FromBytes)]
+#[repr(C)]
+struct SplitDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Copy, Clone, Default, AsBytes, FromBytes)]
+#[repr(C)]
+struct PackedDesc {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+#[derive(Copy, Clone, Default, AsBytes, FromBytes)]
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// Which virtqueue layout was negotiated with the driver. `VIRTIO_F_RING_PACKED` selects
+// `Packed`; otherwise the (default) split layout is used.
+enum Layout {
+    Split {
+        desc_table: VolatileSlice<'static>,
+        avail_ring: VolatileSlice<'static>,
+        used_ring: VolatileSlice<'static>,
+        next_avail: u16,
+        next_used: u16,
+    },
+    Packed {
+        desc_ring: VolatileSlice<'static>,
+        // The driver and device each keep their own wrap counter; it flips every time the
+        // respective side walks past the end of the descriptor ring.
+        next_avail_idx: u16,
+        driver_wrap_counter: bool,
+        next_used_idx: u16,
+        device_wrap_counter: bool,
+    },
+}
+
+/// A virtqueue used by the VVU driver to exchange vhost-user messages with the device, over
+/// either the split or the packed descriptor layout.
+pub struct UserQueue {
+    queue_size: u16,
+    layout: Layout,
+}
+
+/// The part of a [`UserQueue`]'s state that changes as messages are exchanged, captured by
+/// [`UserQueue::snapshot`] and reapplied by [`UserQueue::restore`]. `driver_wrap_counter` and
+/// `device_wrap_counter` are unused (and left `false`) for the split layout, which has no wrap
+/// counters of its own.
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub next_avail: u16,
+    pub next_used: u16,
+    pub driver_wrap_counter: bool,
+    pub device_wrap_counter: bool,
+}
+
+/// A contiguous run of bytes backing one descriptor, handed back by [`UserQueue::read_data`].
+pub struct DescriptorChain {
+    slice: VolatileSlice<'static>,
+}
+
+impl DescriptorChain {
+    pub fn size(&self) -> usize {
+        self.slice.size() as usize
+    }
+
+    pub fn copy_to(&self, buf: &mut [u8]) {
+        self.slice
+            .copy_to(buf)
+            .expect("descriptor chain shorter than destination buffer");
+    }
+}
+
+impl UserQueue {
+    /// Creates a split-layout queue over the given descriptor table and avail/used rings.
+    pub fn new(
+        queue_size: u16,
+        desc_table: VolatileSlice<'static>,
+        avail_ring: VolatileSlice<'static>,
+        used_ring: VolatileSlice<'static>,
+    ) -> Self {
+        Self {
+            queue_size,
+            layout: Layout::Split {
+                desc_table,
+                avail_ring,
+                used_ring,
+                next_avail: 0,
+                next_used: 0,
+            },
+        }
+    }
+
+    /// Creates a packed-layout queue over a single descriptor ring, used once
+    /// `VIRTIO_F_RING_PACKED` has been negotiated.
+    pub fn new_packed(queue_size: u16, desc_ring: VolatileSlice<'static>) -> Self {
+        Self {
+            queue_size,
+            layout: Layout::Packed {
+                desc_ring,
+                next_avail_idx: 0,
+                driver_wrap_counter: true,
+                next_used_idx: 0,
+                device_wrap_counter: true,
+            },
+        }
+    }
+
+    /// Captures the progress markers (ring indices and, for the packed layout, wrap counters)
+    /// needed to resume this queue later without re-walking descriptors the driver and device
+    /// have already agreed on. The underlying descriptor table/rings themselves aren't part of
+    /// the snapshot since they live in BAR memory that outlives a suspend/restore cycle.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        match &self.layout {
+            Layout::Split {
+                next_avail,
+                next_used,
+                ..
+            } => QueueSnapshot {
+                next_avail: *next_avail,
+                next_used: *next_used,
+                driver_wrap_counter: false,
+                device_wrap_counter: false,
+            },
+            Layout::Packed {
+                next_avail_idx,
+                driver_wrap_counter,
+                next_used_idx,
+                device_wrap_counter,
+                ..
+            } => QueueSnapshot {
+                next_avail: *next_avail_idx,
+                next_used: *next_used_idx,
+                driver_wrap_counter: *driver_wrap_counter,
+                device_wrap_counter: *device_wrap_counter,
+            },
+        }
+    }
+
+    /// Restores progress markers captured by a previous call to [`UserQueue::snapshot`],
+    /// overwriting this queue's current ones. Must be called on a queue over the same descriptor
+    /// layout the snapshot was taken from.
+    pub fn restore(&mut self, snapshot: QueueSnapshot) {
+        match &mut self.layout {
+            Layout::Split {
+                next_avail,
+                next_used,
+                ..
+            } => {
+                *next_avail = snapshot.next_avail;
+                *next_used = snapshot.next_used;
+            }
+            Layout::Packed {
+                next_avail_idx,
+                driver_wrap_counter,
+                next_used_idx,
+                device_wrap_counter,
+                ..
+            } => {
+                *next_avail_idx = snapshot.next_avail;
+                *next_used_idx = snapshot.next_used;
+                *driver_wrap_counter = snapshot.driver_wrap_counter;
+                *device_wrap_counter = snapshot.device_wrap_counter;
+            }
+        }
+    }
+
+    fn split_desc(desc_table: &VolatileSlice<'static>, index: u16) -> Result<SplitDesc> {
+        desc_table
+            .get_ref::<SplitDesc>(index as u64 * mem::size_of::<SplitDesc>() as u64)
+            .context("failed to read split descriptor")?
+            .load()
+            .map_err(|e: VolatileMemoryError| e.into())
+    }
+
+    fn packed_desc(desc_ring: &VolatileSlice<'static>, index: u16) -> Result<PackedDesc> {
+        desc_ring
+            .get_ref::<PackedDesc>(index as u64 * mem::size_of::<PackedDesc>() as u64)
+            .context("failed to read packed descriptor")?
+            .load()
+            .map_err(|e: VolatileMemoryError| e.into())
+    }
+
+    /// Reads the next available descriptor chain, if the driver has made one available, without
+    /// blocking.
+    pub fn read_data(&mut self) -> Result<Option<DescriptorChain>> {
+        match &mut self.layout {
+            Layout::Split {
+                desc_table,
+                avail_ring,
+                next_avail,
+                ..
+            } => {
+                // avail_ring layout: flags(u16), idx(u16), ring[queue_size](u16), ...
+                let avail_idx: u16 = avail_ring
+                    .get_ref::<u16>(2)
+                    .context("avail idx")?
+                    .load()
+                    .map_err(VolatileMemoryError::from)?;
+                if *next_avail == avail_idx {
+                    return Ok(None);
+                }
+
+                let ring_offset = 4 + (*next_avail % self.queue_size) as u64 * 2;
+                let desc_index: u16 = avail_ring
+                    .get_ref::<u16>(ring_offset)
+                    .context("avail ring entry")?
+                    .load()
+                    .map_err(VolatileMemoryError::from)?;
+
+                let desc = Self::split_desc(desc_table, desc_index)?;
+                if desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+                    bail!("chained descriptors are not supported on the VVU rxq");
+                }
+                let slice = desc_table
+                    .sub_slice(desc.addr, desc.len as u64)
+                    .context("descriptor data out of bounds")?;
+
+                *next_avail = next_avail.wrapping_add(1);
+                Ok(Some(DescriptorChain { slice }))
+            }
+            Layout::Packed {
+                desc_ring,
+                next_avail_idx,
+                driver_wrap_counter,
+                ..
+            } => {
+                let desc = Self::packed_desc(desc_ring, *next_avail_idx)?;
+                let avail = desc.flags & VIRTQ_DESC_F_AVAIL != 0;
+                let used = desc.flags & VIRTQ_DESC_F_USED != 0;
+                // A descriptor is available to the device iff its AVAIL flag matches the
+                // current driver wrap counter and its USED flag doesn't.
+                if avail != *driver_wrap_counter || used == *driver_wrap_counter {
+                    return Ok(None);
+                }
+
+                let slice = desc_ring
+                    .sub_slice(desc.addr, desc.len as u64)
+                    .context("descriptor data out of bounds")?;
+
+                *next_avail_idx += 1;
+                if *next_avail_idx >= self.queue_size {
+                    *next_avail_idx = 0;
+                    *driver_wrap_counter = !*driver_wrap_counter;
+                }
+
+                Ok(Some(DescriptorChain { slice }))
+            }
+        }
+    }
+
+    /// Writes `data` into the next available descriptor and marks it used so the driver can
+    /// reclaim it.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.layout {
+            Layout::Split {
+                desc_table,
+                avail_ring,
+                used_ring,
+                next_used,
+                ..
+            } => {
+                let ring_offset = 4 + (*next_used % self.queue_size) as u64 * 2;
+                let desc_index: u16 = avail_ring
+                    .get_ref::<u16>(ring_offset)
+                    .context("avail ring entry")?
+                    .load()
+                    .map_err(VolatileMemoryError::from)?;
+                let desc = Self::split_desc(desc_table, desc_index)?;
+                if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                    bail!("descriptor {} is not device-writable", desc_index);
+                }
+                if data.len() > desc.len as usize {
+                    bail!("data larger than descriptor buffer");
+                }
+                let slice = desc_table
+                    .sub_slice(desc.addr, data.len() as u64)
+                    .context("descriptor data out of bounds")?;
+                slice.copy_from(data);
+
+                // used_ring layout: flags(u16), idx(u16), ring[queue_size](UsedElem), ...
+                let used_offset = 4 + (*next_used % self.queue_size) as u64 * 8;
+                used_ring
+                    .get_ref::<UsedElem>(used_offset)
+                    .context("used ring entry")?
+                    .store(UsedElem {
+                        id: desc_index as u32,
+                        len: data.len() as u32,
+                    });
+
+                *next_used = next_used.wrapping_add(1);
+                used_ring
+                    .get_ref::<u16>(2)
+                    .context("used idx")?
+                    .store(*next_used);
+                Ok(())
+            }
+            Layout::Packed {
+                desc_ring,
+                next_used_idx,
+                device_wrap_counter,
+                ..
+            } => {
+                let index = *next_used_idx;
+                let mut desc = Self::packed_desc(desc_ring, index)?;
+                if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                    bail!("descriptor {} is not device-writable", index);
+                }
+                if data.len() > desc.len as usize {
+                    bail!("data larger than descriptor buffer");
+                }
+                let slice = desc_ring
+                    .sub_slice(desc.addr, data.len() as u64)
+                    .context("descriptor data out of bounds")?;
+                slice.copy_from(data);
+
+                desc.len = data.len() as u32;
+                let mut flags = if *device_wrap_counter {
+                    VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+                } else {
+                    0
+                };
+                // Keep the flag that isn't owned by the "used" write unchanged: both AVAIL
+                // and USED flip together with the device's wrap counter when it publishes a
+                // completion.
+                flags |= desc.flags & !(VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED);
+                desc.flags = flags;
+
+                desc_ring
+                    .get_ref::<PackedDesc>(index as u64 * mem::size_of::<PackedDesc>() as u64)
+                    .context("packed descriptor")?
+                    .store(desc);
+
+                *next_used_idx += 1;
+                if *next_used_idx >= self.queue_size {
+                    *next_used_idx = 0;
+                    *device_wrap_counter = !*device_wrap_counter;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Consumes the notification that the driver has finished reading back a previously
+    /// completed tx buffer. Neither layout needs bookkeeping here, since `write` already advances
+    /// the used index (split) or sets the USED flag and device wrap counter (packed), so this does
+    /// nothing; the method exists so callers don't need to know which layout is active.
+    pub fn ack_used(&mut self) -> Result<()> {
+        Ok(())
+    }
+}